@@ -3,38 +3,80 @@
 //
 
 use std::fmt::Debug;
-use std::io::Write;
+use std::io::{IoSlice, Write};
+use std::mem::MaybeUninit;
 use std::num::Wrapping;
+use std::ptr;
 
 use vm_memory::{VolatileMemoryError, VolatileSlice, WriteVolatile};
 
-use super::{VsockCsmError, defs};
+use super::VsockCsmError;
+#[cfg(test)]
+use super::defs;
 use crate::utils::wrap_usize_to_u32;
 use crate::vstate::memory::{BitmapSlice, Bytes};
 
 /// A simple ring-buffer implementation, used by vsock connections to buffer TX (guest -> host)
 /// data.  Memory for this buffer is allocated lazily, since buffering will only be needed when
-/// the host can't read fast enough.
+/// the host can't read fast enough. The backing allocation starts small and doubles on demand,
+/// up to a per-connection configured maximum, so that bursty connections don't have to pay for
+/// worst-case buffering up front.
 #[derive(Debug)]
 pub struct TxBuf {
-    /// The actual u8 buffer - only allocated after the first push.
-    data: Option<Box<[u8]>>,
+    /// The actual u8 buffer - only allocated after the first push, and grown (by doubling) as
+    /// needed, up to `max_size`. `data.capacity()` is the current backing allocation size; bytes
+    /// past `data.len()` are reserved but not yet written to by any `push`, so they're left
+    /// alone rather than zero-filled - `data.len()` only ever grows far enough to cover the
+    /// highest offset a `push` has touched.
+    data: Vec<u8>,
+    /// The modulus used to map the free-running `head`/`tail` counters to offsets into `data`.
+    /// Kept as an explicit field - rather than read off `data.capacity()` - because it must
+    /// always be a power of two: `head`/`tail` wrap around at `u32::MAX`, and `counter % size`
+    /// only stays continuous across that wraparound if `size` divides `2^32` evenly. A
+    /// non-power-of-two modulus would silently corrupt the stream the first time over 4 GiB
+    /// flows through one long-lived connection. `Vec::with_capacity` is also free to
+    /// over-allocate, so `data.capacity()` isn't guaranteed to equal what was requested anyway.
+    size: usize,
     /// Ring-buffer head offset - where new data is pushed to.
     head: Wrapping<u32>,
     /// Ring-buffer tail offset - where data is flushed from.
     tail: Wrapping<u32>,
+    /// The largest this buffer is allowed to grow to, in bytes.
+    max_size: usize,
+}
+
+/// Advances a `TxBuf`'s `tail` by `written` bytes (relative to `start`) when dropped, no matter
+/// how the scope holding it is exited - normal return, an early `?`, or a panic unwinding out of
+/// a write call. This keeps `tail` accounting for exactly what was drained, without relying on
+/// every exit path to remember to update it by hand.
+struct TailAdvanceGuard<'a> {
+    tail: &'a mut Wrapping<u32>,
+    start: Wrapping<u32>,
+    written: usize,
+}
+
+impl Drop for TailAdvanceGuard<'_> {
+    fn drop(&mut self) {
+        *self.tail = self.start + Wrapping(wrap_usize_to_u32(self.written));
+    }
 }
 
 impl TxBuf {
-    /// Total buffer size, in bytes.
-    const SIZE: usize = defs::CONN_TX_BUF_SIZE as usize;
+    /// The size of the first allocation backing this buffer, in bytes. Chosen to be large enough
+    /// to absorb a handful of packets without forcing an immediate reallocation.
+    const INITIAL_SIZE: usize = 4 * 1024;
 
     /// Ring-buffer constructor.
-    pub fn new() -> Self {
+    ///
+    /// `max_size` is the upper bound this buffer is allowed to grow to, in bytes. It's up to the
+    /// caller to plumb in a value derived from device/connection configuration.
+    pub fn new(max_size: u32) -> Self {
         Self {
-            data: None,
+            data: Vec::new(),
+            size: 0,
             head: Wrapping(0),
             tail: Wrapping(0),
+            max_size: max_size as usize,
         }
     }
 
@@ -44,36 +86,137 @@ impl TxBuf {
         (self.head - self.tail).0 as usize
     }
 
+    /// Get the modulus `head`/`tail` are currently mapped against, in bytes. This is `0` before
+    /// the first push, and grows (but never shrinks, and always stays a power of two) up to
+    /// roughly `max_size` afterwards.
+    fn capacity(&self) -> usize {
+        self.size
+    }
+
+    /// Make sure the backing allocation can hold at least `min_capacity` bytes, growing it by
+    /// doubling (up to `max_size`, rounded up to the next power of two, but never below
+    /// `INITIAL_SIZE`) if necessary, and copying over the live `[tail, head)` region.
+    ///
+    /// Callers are expected to have already checked that `min_capacity <= self.max_size`.
+    fn grow(&mut self, min_capacity: usize) {
+        debug_assert!(min_capacity <= self.max_size);
+
+        let current_capacity = self.size;
+        // Rounding up to the next power of two - rather than capping exactly at `max_size` - is
+        // what keeps `size` a valid ring-buffer modulus even when `max_size` itself isn't a
+        // power of two; the `new_len > self.max_size` check in `push` is what actually enforces
+        // the configured limit on live data, so over-allocating the backing store slightly
+        // doesn't let more data pile up than `max_size` allows.
+        let mut new_capacity =
+            std::cmp::max(current_capacity, Self::INITIAL_SIZE).next_power_of_two();
+        while new_capacity < min_capacity {
+            new_capacity *= 2;
+        }
+
+        if new_capacity <= current_capacity {
+            return;
+        }
+        debug_assert!(new_capacity.is_power_of_two());
+
+        let len = self.len();
+        let mut new_data = Vec::with_capacity(new_capacity);
+
+        if !self.data.is_empty() {
+            let tail_ofs = self.tail.0 as usize % current_capacity;
+            let first_len = std::cmp::min(current_capacity - tail_ofs, len);
+
+            // SAFETY: `new_data` was just allocated with room for at least `len` bytes. The
+            // source ranges (`self.data[tail_ofs..]` and `self.data[..len - first_len]`) lie
+            // within `self.data`'s own initialized prefix, since they're part of the live
+            // `[tail, head)` region. Copying through raw pointers - instead of e.g. zero-filling
+            // `new_data` up front so a safe slice copy could be used - means the rest of the new,
+            // larger allocation is never touched until an actual `push` writes to it.
+            unsafe {
+                let dst = new_data.as_mut_ptr();
+                ptr::copy_nonoverlapping(self.data.as_ptr().add(tail_ofs), dst, first_len);
+                if first_len < len {
+                    ptr::copy_nonoverlapping(
+                        self.data.as_ptr(),
+                        dst.add(first_len),
+                        len - first_len,
+                    );
+                }
+                new_data.set_len(len);
+            }
+        }
+
+        self.data = new_data;
+        self.size = new_capacity;
+        // The live data now starts at the very beginning of the new allocation, so the wrapping
+        // offsets need to be rebased accordingly.
+        self.tail = Wrapping(0);
+        self.head = Wrapping(wrap_usize_to_u32(len));
+    }
+
     /// Push a byte slice onto the ring-buffer.
     ///
     /// Either the entire source slice will be pushed to the ring-buffer, or none of it, if
-    /// there isn't enough room, in which case `Err(Error::TxBufFull)` is returned.
+    /// there isn't enough room, in which case `Err(Error::TxBufFull)` is returned. If the
+    /// current backing allocation is too small to hold the result, but growing up to `max_size`
+    /// would make room, the buffer is grown first.
     pub fn push(&mut self, src: &VolatileSlice<impl BitmapSlice>) -> Result<(), VsockCsmError> {
-        // Error out if there's no room to push the entire slice.
-        if self.len() + src.len() > Self::SIZE {
+        let new_len = self.len() + src.len();
+
+        // Error out if there's no room to push the entire slice, even after growing.
+        if new_len > self.max_size {
             return Err(VsockCsmError::TxBufFull);
         }
 
-        let data = self
-            .data
-            .get_or_insert_with(|| vec![0u8; Self::SIZE].into_boxed_slice());
+        if self.data.is_empty() || new_len > self.capacity() {
+            self.grow(new_len);
+        }
+
+        let size = self.capacity();
 
         // Buffer head, as an offset into the data slice.
-        let head_ofs = self.head.0 as usize % Self::SIZE;
+        let head_ofs = self.head.0 as usize % size;
 
         // Pushing a slice to this buffer can take either one or two slice copies: - one copy,
-        // if the slice fits between `head_ofs` and `Self::SIZE`; or - two copies, if the
+        // if the slice fits between `head_ofs` and `size`; or - two copies, if the
         // ring-buffer head wraps around.
 
         // First copy length: we can only go from the head offset up to the total buffer size.
-        let len = std::cmp::min(Self::SIZE - head_ofs, src.len());
-
-        let _ = src.read(&mut data[head_ofs..(head_ofs + len)], 0);
+        let len = std::cmp::min(size - head_ofs, src.len());
+        let watermark = std::cmp::max(self.data.len(), head_ofs + len);
+
+        // `VolatileSlice::read` needs a `&mut [u8]` destination, but rather than zero-filling
+        // the backing allocation up front, we write into its reserved-but-uninitialized spare
+        // capacity directly, through a raw pointer - the same technique `grow` uses to move data
+        // into a larger allocation without zero-filling it. Crucially, the write happens
+        // *before* `set_len` below: bumping `data`'s length first (so a safe slice could be
+        // taken) would let a `Vec<u8>` claim bytes are initialized before they actually are,
+        // which is exactly the hazard `MaybeUninit`-style APIs exist to avoid, even though every
+        // `u8` bit pattern is individually valid. The second copy below, if any, only ever
+        // touches offsets the buffer has wrapped past before, which by that point are already
+        // covered by an earlier watermark bump.
+        //
+        // SAFETY: `head_ofs + len <= size <= self.data.capacity()` (the latter from `grow`
+        // above), so the pointer and length stay within `self.data`'s allocation.
+        unsafe {
+            let dst = std::slice::from_raw_parts_mut(self.data.as_mut_ptr().add(head_ofs), len);
+            let _ = src.read(dst, 0);
+        }
 
         // If the slice didn't fit, the buffer head will wrap around, and pushing continues
         // from the start of the buffer (`&self.data[0]`).
         if len < src.len() {
-            let _ = src.read(&mut data[..(src.len() - len)], len);
+            let second_len = src.len() - len;
+            // SAFETY: `second_len <= size <= self.data.capacity()`, same reasoning as above.
+            unsafe {
+                let dst = std::slice::from_raw_parts_mut(self.data.as_mut_ptr(), second_len);
+                let _ = src.read(dst, len);
+            }
+        }
+
+        if self.data.len() < watermark {
+            // SAFETY: every byte up to `watermark` has just been written above, either by this
+            // push's first copy or (for a wrapped second copy) by an earlier one.
+            unsafe { self.data.set_len(watermark) };
         }
 
         // Either way, we've just pushed exactly `src.len()` bytes, so that's the amount by
@@ -83,8 +226,92 @@ impl TxBuf {
         Ok(())
     }
 
+    /// Expose the largest contiguous writable region of the backing allocation, starting at the
+    /// current head offset, so a caller that can produce its data in-place (e.g. reading a
+    /// descriptor chain straight off guest memory) can skip the extra copy through an
+    /// intermediate buffer that `push` would otherwise require. The caller writes into (a
+    /// prefix of) the returned slice, then calls [`Self::commit`] with however many bytes it
+    /// actually wrote.
+    ///
+    /// Lazily allocates the initial backing allocation if this is the first write to the
+    /// buffer, same as `push` does. The returned slice is empty if the buffer is already full -
+    /// up to `max_size`, which may be smaller than the backing allocation's own (power-of-two,
+    /// possibly overshot) capacity.
+    ///
+    /// The returned slice is `MaybeUninit<u8>`, not `u8`: as with `push`, these bytes are
+    /// reserved-but-uninitialized capacity, and unlike `push`, this function doesn't fully
+    /// overwrite them itself, so there's no guarantee the whole slice becomes initialized before
+    /// `commit` is called. Returning `&mut [u8]` here - per `BufWriter::buffer_mut` - would let a
+    /// caller that writes only a prefix, or reads before writing, observe uninitialized memory
+    /// through a type that promises otherwise; `MaybeUninit` makes that impossible to do safely.
+    pub fn spare_slice_mut(&mut self) -> &mut [MaybeUninit<u8>] {
+        if self.data.is_empty() {
+            self.grow(std::cmp::min(Self::INITIAL_SIZE, self.max_size));
+        }
+
+        let size = self.capacity();
+        let head_ofs = self.head.0 as usize % size;
+        // Bound the free region by `max_size`, not just the (possibly larger, power-of-two
+        // rounded) backing allocation - `size` is what `head`/`tail` wrap against, but
+        // `max_size` is the actual cap on live data `push` enforces, and this function has to
+        // enforce it too rather than let a caller drive `len()` past it via `commit`.
+        let free = std::cmp::min(size - self.len(), self.max_size - self.len());
+
+        // Same reasoning as in `push`: the free region can't extend past the end of the
+        // backing allocation without wrapping, so only the first segment - `[head_ofs, size)`,
+        // or `[head_ofs, tail_ofs)` if that's shorter - is ever handed back in one call. A
+        // second call, after the caller `commit`s and the head wraps, exposes the rest.
+        let end = head_ofs + std::cmp::min(size - head_ofs, free);
+
+        // Unlike `push`, the bytes in `[head_ofs, end)` aren't written by this function - the
+        // caller fills in (a prefix of) the returned slice itself, and only `commit` finds out
+        // how much of it actually became initialized. So, unlike `push`, `data`'s length isn't
+        // bumped here at all; `commit` is solely responsible for that, once it's actually true.
+        //
+        // SAFETY: `end <= size <= self.data.capacity()`, so the pointer and length stay within
+        // `self.data`'s allocation. `MaybeUninit<u8>` has the same layout as `u8`, so casting the
+        // pointer is valid regardless of whether the bytes it points to are initialized.
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                self.data
+                    .as_mut_ptr()
+                    .add(head_ofs)
+                    .cast::<MaybeUninit<u8>>(),
+                end - head_ofs,
+            )
+        }
+    }
+
+    /// Advance the ring-buffer head by `n` bytes, after the caller has filled in (a prefix of)
+    /// the slice previously returned by [`Self::spare_slice_mut`].
+    pub fn commit(&mut self, n: usize) {
+        let size = self.capacity();
+        let head_ofs = self.head.0 as usize % size;
+        let free = std::cmp::min(size - self.len(), self.max_size - self.len());
+
+        debug_assert!(n <= std::cmp::min(size - head_ofs, free));
+
+        let watermark = std::cmp::max(self.data.len(), head_ofs + n);
+        if self.data.len() < watermark {
+            // SAFETY: `commit`'s contract is that the caller has just written `n` bytes starting
+            // at `head_ofs` into the slice `spare_slice_mut` returned, so every byte up to
+            // `watermark` is genuinely initialized by now.
+            unsafe { self.data.set_len(watermark) };
+        }
+
+        self.head += wrap_usize_to_u32(n);
+    }
+
     /// Flush the contents of the ring-buffer to a writable stream.
     ///
+    /// Attempts writes until the buffer empties out, or a write returns `Ok(0)`, a short count,
+    /// or a `WouldBlock` error, at which point there's no point retrying right away. A
+    /// `WouldBlock` hit before anything was drained is returned as an error; one hit after a
+    /// partial drain ends the flush with `Ok` and the count drained so far. On a hard
+    /// error, `tail` is still advanced for whatever was successfully drained before the error,
+    /// and the error is propagated to the caller - callers get accurate accounting either way,
+    /// and won't double-send already-flushed data on retry.
+    ///
     /// Return the number of bytes that have been transferred out of the ring-buffer and into
     /// the writable stream.
     pub fn flush_to<W: Write + Debug>(&mut self, sink: &mut W) -> Result<usize, VsockCsmError> {
@@ -93,42 +320,72 @@ impl TxBuf {
             return Ok(0);
         }
 
-        // Buffer tail, as an offset into the buffer data slice.
-        let tail_ofs = self.tail.0 as usize % Self::SIZE;
-
-        // Flushing the buffer can take either one or two writes:
-        // - one write, if the tail doesn't need to wrap around to reach the head; or
-        // - two writes, if the tail would wrap around: tail to slice end, then slice end to head.
-
-        // First write length: the lesser of tail to slice end, or tail to head.
-        let len_to_write = std::cmp::min(Self::SIZE - tail_ofs, self.len());
-
-        // It's safe to unwrap here, since we've already checked if the buffer was empty.
-        let data = self.data.as_ref().unwrap();
-
-        // Issue the first write and absorb any `WouldBlock` error (we can just try again
-        // later).
-        let written = sink
-            .write(&data[tail_ofs..(tail_ofs + len_to_write)])
-            .map_err(VsockCsmError::TxBufFlush)?;
-
-        // Move the buffer tail ahead by the amount (of bytes) we were able to flush out.
-        self.tail += wrap_usize_to_u32(written);
-
-        // If we weren't able to flush out as much as we tried, there's no point in attempting
-        // our second write.
-        if written < len_to_write {
-            return Ok(written);
+        let size = self.capacity();
+        let data = &self.data;
+        let head = self.head;
+        let start_tail = self.tail;
+
+        // `guard` advances `self.tail` by however many bytes were actually written, even if a
+        // `sink.write`/`sink.write_vectored` call below returns an error or panics partway
+        // through the loop - mirroring the guard `BufWriter::flush_buf` uses for the same
+        // reason: callers must never see `tail` left behind what was truly drained (which would
+        // cause already-sent bytes to be resent), nor advanced past it (which would silently
+        // drop data).
+        let mut guard = TailAdvanceGuard {
+            tail: &mut self.tail,
+            start: start_tail,
+            written: 0,
+        };
+
+        while guard.written < (head - start_tail).0 as usize {
+            let tail = start_tail + Wrapping(wrap_usize_to_u32(guard.written));
+            let tail_ofs = tail.0 as usize % size;
+            let head_ofs = head.0 as usize % size;
+            let remaining = (head - tail).0 as usize;
+
+            // A write can cover either one or two ring-buffer segments:
+            // - one, if the tail doesn't need to wrap around to reach the head; or
+            // - two, if the tail would wrap around: tail to slice end, then slice end to head.
+            let len_to_write = std::cmp::min(size - tail_ofs, remaining);
+
+            // If the live data wraps around the end of `data`, flush both segments -
+            // `[tail_ofs, size)` and `[0, head_ofs)` - with a single `write_vectored` call,
+            // instead of two separate `write` syscalls. `Write::is_write_vectored` isn't
+            // available on stable Rust to check this ahead of time, but `write_vectored` falls
+            // back to a single `write` of the first non-empty buffer for sinks that don't
+            // override it, so this is never worse than the non-vectored path below - and the
+            // loop just picks up the rest of the second segment on its next iteration.
+            let result = if len_to_write < remaining {
+                let bufs = [
+                    IoSlice::new(&data[tail_ofs..size]),
+                    IoSlice::new(&data[..head_ofs]),
+                ];
+                sink.write_vectored(&bufs)
+            } else {
+                sink.write(&data[tail_ofs..(tail_ofs + len_to_write)])
+            };
+            let written = match result {
+                Ok(written) => written,
+                // Once some data has been drained, a `WouldBlock` just ends this flush early,
+                // the same way a short write does.
+                Err(err)
+                    if err.kind() == std::io::ErrorKind::WouldBlock && guard.written > 0 =>
+                {
+                    break;
+                }
+                Err(err) => return Err(VsockCsmError::TxBufFlush(err)),
+            };
+
+            guard.written += written;
+
+            // If we weren't able to flush out as much as this segment held, there's no point
+            // attempting another write right away.
+            if written == 0 || written < len_to_write {
+                break;
+            }
         }
 
-        // Attempt our second write. This will return immediately if a second write isn't
-        // needed, since checking for an empty buffer is the first thing we do in this
-        // function.
-        //
-        // Interesting corner case: if we've already written some data in the first pass,
-        // and then the second write fails, we will consider the flush action a success
-        // and return the number of bytes written in the first pass.
-        Ok(written + self.flush_to(sink).unwrap_or(0))
+        Ok(guard.written)
     }
 
     /// Check if the buffer holds any data that hasn't yet been flushed out.
@@ -154,27 +411,58 @@ mod tests {
 
     use super::*;
 
+    /// Default maximum buffer size used by tests that don't care about a specific limit.
+    const MAX_SIZE: usize = defs::CONN_TX_BUF_SIZE as usize;
+
     #[derive(Debug)]
     struct TestSink {
         data: Vec<u8>,
         err: Option<IoError>,
+        /// How many writes (of either kind) should succeed before `err` is returned.
+        fail_after: usize,
         capacity: usize,
+        vectored: bool,
+        write_calls: usize,
+        write_vectored_calls: usize,
     }
 
     impl TestSink {
-        const DEFAULT_CAPACITY: usize = 2 * TxBuf::SIZE;
+        const DEFAULT_CAPACITY: usize = 2 * MAX_SIZE;
         fn new() -> Self {
             Self {
                 data: Vec::with_capacity(Self::DEFAULT_CAPACITY),
                 err: None,
+                fail_after: 0,
                 capacity: Self::DEFAULT_CAPACITY,
+                vectored: true,
+                write_calls: 0,
+                write_vectored_calls: 0,
             }
         }
+
+        fn disable_vectored(mut self) -> Self {
+            self.vectored = false;
+            self
+        }
+
+        /// If `self.err` is set, consumes it (returning `true`) once `fail_after` prior calls
+        /// have already gone through successfully.
+        fn should_fail(&mut self) -> bool {
+            if self.err.is_none() {
+                return false;
+            }
+            if self.fail_after > 0 {
+                self.fail_after -= 1;
+                return false;
+            }
+            true
+        }
     }
 
     impl Write for TestSink {
         fn write(&mut self, src: &[u8]) -> Result<usize, IoError> {
-            if self.err.is_some() {
+            self.write_calls += 1;
+            if self.should_fail() {
                 return Err(self.err.take().unwrap());
             }
             let len_to_push = std::cmp::min(self.capacity - self.data.len(), src.len());
@@ -184,6 +472,45 @@ mod tests {
         fn flush(&mut self) -> Result<(), IoError> {
             Ok(())
         }
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize, IoError> {
+            self.write_vectored_calls += 1;
+
+            // With `vectored` disabled, behave like the default `write_vectored` implementation
+            // a sink that doesn't override it would get: only the first non-empty buffer is
+            // ever written, one `write` at a time.
+            if !self.vectored {
+                return match bufs.iter().find(|b| !b.is_empty()) {
+                    Some(buf) => self.write(buf),
+                    None => Ok(0),
+                };
+            }
+
+            if self.should_fail() {
+                return Err(self.err.take().unwrap());
+            }
+            let mut written = 0;
+            for buf in bufs {
+                let remaining = self.capacity - self.data.len();
+                if remaining == 0 {
+                    break;
+                }
+                let len = std::cmp::min(remaining, buf.len());
+                self.data.extend_from_slice(&buf[..len]);
+                written += len;
+                if len < buf.len() {
+                    break;
+                }
+            }
+            Ok(written)
+        }
+    }
+
+    /// Writes `src` into the start of `dst`, for tests exercising `spare_slice_mut` - which
+    /// returns `MaybeUninit<u8>`, not `u8`, so a plain `copy_from_slice` doesn't apply.
+    fn write_spare(dst: &mut [MaybeUninit<u8>], src: &[u8]) {
+        for (d, &s) in dst.iter_mut().zip(src) {
+            d.write(s);
+        }
     }
 
     impl TestSink {
@@ -194,6 +521,10 @@ mod tests {
         fn set_err(&mut self, err: IoError) {
             self.err = Some(err);
         }
+        fn set_err_after(&mut self, err: IoError, fail_after: usize) {
+            self.err = Some(err);
+            self.fail_after = fail_after;
+        }
         fn set_capacity(&mut self, capacity: usize) {
             self.capacity = capacity;
             if self.data.len() > self.capacity {
@@ -204,11 +535,11 @@ mod tests {
 
     #[test]
     fn test_push_nowrap() {
-        let mut txbuf = TxBuf::new();
+        let mut txbuf = TxBuf::new(MAX_SIZE as u32);
         let mut sink = TestSink::new();
         assert!(txbuf.is_empty());
 
-        assert!(txbuf.data.is_none());
+        assert!(txbuf.data.is_empty());
 
         txbuf
             .push(&VolatileSlice::from([1, 2, 3, 4].as_mut_slice()))
@@ -234,9 +565,9 @@ mod tests {
 
     #[test]
     fn test_push_wrap() {
-        let mut txbuf = TxBuf::new();
+        let mut txbuf = TxBuf::new(MAX_SIZE as u32);
         let mut sink = TestSink::new();
-        let mut tmp: Vec<u8> = vec![0; TxBuf::SIZE - 2];
+        let mut tmp: Vec<u8> = vec![0; MAX_SIZE - 2];
         txbuf
             .push(&VolatileSlice::from(tmp.as_mut_slice()))
             .unwrap();
@@ -248,7 +579,19 @@ mod tests {
             .unwrap();
         assert_eq!(txbuf.flush_to(&mut sink).unwrap(), 4);
         assert_eq!(sink.data, [1, 2, 3, 4]);
+        // The push above made the buffer wrap around, so the flush should have gone out as a
+        // single vectored write, rather than two separate `write` calls.
+        assert_eq!(sink.write_vectored_calls, 1);
+
+        sink.clear();
 
+        // Push the tail offset back up to the end of the buffer, so that the next small push
+        // wraps around again.
+        let mut tmp: Vec<u8> = vec![0; MAX_SIZE - 2 - 2];
+        txbuf
+            .push(&VolatileSlice::from(tmp.as_mut_slice()))
+            .unwrap();
+        txbuf.flush_to(&mut sink).unwrap();
         sink.clear();
 
         txbuf
@@ -256,14 +599,15 @@ mod tests {
             .unwrap();
         assert_eq!(txbuf.flush_to(&mut sink).unwrap(), 4);
         assert_eq!(sink.data, [5, 6, 7, 8]);
+        assert_eq!(sink.write_vectored_calls, 2);
     }
 
     #[test]
     fn test_push_error() {
-        let mut txbuf = TxBuf::new();
-        let mut tmp = Vec::with_capacity(TxBuf::SIZE);
+        let mut txbuf = TxBuf::new(MAX_SIZE as u32);
+        let mut tmp = Vec::with_capacity(MAX_SIZE);
 
-        tmp.resize(TxBuf::SIZE - 1, 0);
+        tmp.resize(MAX_SIZE - 1, 0);
         txbuf
             .push(&VolatileSlice::from(tmp.as_mut_slice()))
             .unwrap();
@@ -284,7 +628,7 @@ mod tests {
 
     #[test]
     fn test_incomplete_flush() {
-        let mut txbuf = TxBuf::new();
+        let mut txbuf = TxBuf::new(MAX_SIZE as u32);
         let mut sink = TestSink::new();
 
         sink.set_capacity(2);
@@ -305,7 +649,7 @@ mod tests {
     fn test_flush_error() {
         const EACCESS: i32 = 13;
 
-        let mut txbuf = TxBuf::new();
+        let mut txbuf = TxBuf::new(MAX_SIZE as u32);
         let mut sink = TestSink::new();
 
         txbuf
@@ -319,4 +663,152 @@ mod tests {
             other => panic!("Unexpected result: {:?}", other),
         }
     }
+
+    #[test]
+    fn test_flush_error_after_partial_drain() {
+        const EACCESS: i32 = 13;
+
+        let mut txbuf = TxBuf::new(MAX_SIZE as u32);
+        // Disable vectored writes, so a wrapped flush takes two separate `write` calls, and we
+        // can make the second one fail after the first one succeeded.
+        let mut sink = TestSink::new().disable_vectored();
+
+        let mut tmp: Vec<u8> = vec![0; MAX_SIZE - 2];
+        txbuf
+            .push(&VolatileSlice::from(tmp.as_mut_slice()))
+            .unwrap();
+        txbuf.flush_to(&mut sink).unwrap();
+        sink.clear();
+
+        // This push wraps the ring buffer, so flushing it takes two writes.
+        txbuf
+            .push(&VolatileSlice::from([1, 2, 3, 4].as_mut_slice()))
+            .unwrap();
+        sink.set_err_after(IoError::from_raw_os_error(EACCESS), 1);
+
+        match txbuf.flush_to(&mut sink) {
+            Err(VsockCsmError::TxBufFlush(ref err))
+                if err.kind() == ErrorKind::PermissionDenied => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        // The first write succeeded before the second one failed - the error is reported, and
+        // that partial progress is still reflected in `tail`, so it isn't double-counted on a
+        // retry.
+        assert_eq!(sink.data, [1, 2]);
+        assert_eq!(txbuf.len(), 2);
+    }
+
+    #[test]
+    fn test_grow() {
+        let mut txbuf = TxBuf::new(MAX_SIZE as u32);
+        assert_eq!(txbuf.capacity(), 0);
+
+        // A small push only grows the buffer to the initial size.
+        txbuf
+            .push(&VolatileSlice::from([1, 2, 3, 4].as_mut_slice()))
+            .unwrap();
+        assert_eq!(txbuf.capacity(), TxBuf::INITIAL_SIZE);
+        // Only the bytes this push actually touched are ever marked initialized - the rest of
+        // the (much larger) initial allocation is left untouched rather than zero-filled.
+        assert_eq!(txbuf.data.len(), 4);
+
+        // A push that doesn't fit in the current allocation, but does fit under `max_size`,
+        // causes the buffer to double (repeatedly) instead of returning `TxBufFull`.
+        let mut tmp = vec![0u8; TxBuf::INITIAL_SIZE];
+        txbuf.push(&VolatileSlice::from(tmp.as_mut_slice())).unwrap();
+        assert!(txbuf.capacity() > TxBuf::INITIAL_SIZE);
+        assert!(txbuf.capacity() <= MAX_SIZE);
+
+        let mut sink = TestSink::new();
+        assert_eq!(
+            txbuf.flush_to(&mut sink).unwrap(),
+            4 + TxBuf::INITIAL_SIZE
+        );
+        assert_eq!(sink.data[..4], [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_spare_slice_mut_nowrap() {
+        let mut txbuf = TxBuf::new(MAX_SIZE as u32);
+        assert_eq!(txbuf.capacity(), 0);
+
+        // The first call lazily allocates the initial backing allocation, same as `push` does.
+        let spare = txbuf.spare_slice_mut();
+        assert_eq!(spare.len(), TxBuf::INITIAL_SIZE);
+        write_spare(&mut spare[..4], &[1, 2, 3, 4]);
+        assert_eq!(txbuf.capacity(), TxBuf::INITIAL_SIZE);
+        txbuf.commit(4);
+        assert_eq!(txbuf.len(), 4);
+
+        let mut sink = TestSink::new();
+        assert_eq!(txbuf.flush_to(&mut sink).unwrap(), 4);
+        assert_eq!(sink.data, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_spare_slice_mut_wrap() {
+        let mut txbuf = TxBuf::new(MAX_SIZE as u32);
+        let mut sink = TestSink::new();
+
+        // Push up to 2 bytes short of the end of the buffer, then drain it, so the head sits
+        // right at the point where the next write has to wrap.
+        let mut tmp: Vec<u8> = vec![0; MAX_SIZE - 2];
+        txbuf
+            .push(&VolatileSlice::from(tmp.as_mut_slice()))
+            .unwrap();
+        txbuf.flush_to(&mut sink).unwrap();
+        sink.clear();
+
+        // Only the 2 remaining bytes up to the end of the allocation are handed back in one
+        // call, even though there's more free space past the wraparound point.
+        let spare = txbuf.spare_slice_mut();
+        assert_eq!(spare.len(), 2);
+        write_spare(spare, &[1, 2]);
+        txbuf.commit(2);
+
+        // The rest of the free space only becomes available, as a fresh contiguous slice, once
+        // the head has actually wrapped around.
+        let spare = txbuf.spare_slice_mut();
+        assert_eq!(spare.len(), MAX_SIZE - 2);
+        write_spare(&mut spare[..2], &[3, 4]);
+        txbuf.commit(2);
+
+        assert_eq!(txbuf.flush_to(&mut sink).unwrap(), 4);
+        assert_eq!(sink.data, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_spare_slice_mut_full() {
+        let mut txbuf = TxBuf::new(MAX_SIZE as u32);
+
+        let mut tmp: Vec<u8> = vec![0; MAX_SIZE];
+        txbuf
+            .push(&VolatileSlice::from(tmp.as_mut_slice()))
+            .unwrap();
+
+        assert!(txbuf.spare_slice_mut().is_empty());
+    }
+
+    #[test]
+    fn test_spare_slice_mut_respects_non_power_of_two_max_size() {
+        // A `max_size` that isn't a power of two rounds up to a larger backing allocation (see
+        // `grow`) - `spare_slice_mut`/`commit` must still cap live data at `max_size`
+        // itself, rather than at that larger allocation.
+        const ODD_MAX_SIZE: u32 = 100_000;
+        let mut txbuf = TxBuf::new(ODD_MAX_SIZE);
+
+        let mut tmp: Vec<u8> = vec![0; ODD_MAX_SIZE as usize - 4];
+        txbuf
+            .push(&VolatileSlice::from(tmp.as_mut_slice()))
+            .unwrap();
+        assert!(txbuf.capacity() > ODD_MAX_SIZE as usize);
+
+        let spare = txbuf.spare_slice_mut();
+        assert_eq!(spare.len(), 4);
+        write_spare(spare, &[1, 2, 3, 4]);
+        txbuf.commit(4);
+
+        assert_eq!(txbuf.len(), ODD_MAX_SIZE as usize);
+        assert!(txbuf.spare_slice_mut().is_empty());
+    }
 }